@@ -1,7 +1,15 @@
+use std::fs;
+use std::io;
 use std::io::SeekFrom;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::time::Duration;
+
+use cid::{Cid, Codec, Version};
+use curl::easy::{Easy, Form};
+use multihash;
+use serde_json::Value;
+use tar::Archive;
 
 use core::PackageId;
 use hex::ToHex;
@@ -11,25 +19,648 @@ use util::FileLock;
 use util::{Config, Sha256, Filesystem};
 use util::errors::{CargoResult, CargoResultExt};
 
+/// Default address of the local IPFS daemon's HTTP API, used when the
+/// `registries.ipfs.api` config key is not set.
+const DEFAULT_API_ADDR: &str = "http://127.0.0.1:5001";
+
+/// Public HTTP gateways tried, in order, when the local daemon's API is
+/// unreachable and `registries.ipfs.gateways` isn't configured. `{path}`
+/// is replaced with the object's path with the leading slash stripped,
+/// e.g. `ipfs/<cid>/foo-1.0.0.crate`.
+const DEFAULT_GATEWAYS: &[&str] = &[
+    "https://ipfs.io/{path}",
+    "https://dweb.link/{path}",
+];
+
+/// Fetches `path` (e.g. `/ipfs/<cid>/foo`) from the first gateway template
+/// in `gateways` that responds successfully, substituting `{path}`.
+/// `query` is appended to the gateway URL as-is (e.g. `?format=tar`).
+fn fetch_via_gateways(gateways: &[String],
+                       path: &Path,
+                       query: &str,
+                       data: &mut FnMut(&[u8]) -> CargoResult<()>) -> CargoResult<()> {
+    let trimmed = path.to_string_lossy();
+    let trimmed = trimmed.trim_start_matches('/');
+
+    let mut last_err = None;
+    for template in gateways {
+        let url = format!("{}{}", template.replace("{path}", trimmed), query);
+        // Buffer each attempt so a failed gateway's bytes never reach `data`.
+        let mut body = Vec::new();
+        let result = http_get(&url, &mut |buf| {
+            body.extend_from_slice(buf);
+            Ok(())
+        });
+        match result {
+            Ok(()) => return data(&body),
+            Err(e) => {
+                debug!("gateway `{}` failed: {}", url, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| format_err!("no IPFS gateways configured")))
+}
+
+/// Plain HTTP GET, used for public gateways (unlike the local daemon's
+/// `/api/v0/*` endpoints, which are POST-based RPCs).
+fn http_get(url: &str, data: &mut FnMut(&[u8]) -> CargoResult<()>) -> CargoResult<()> {
+    let mut handle = Easy::new();
+    handle.url(url).chain_err(|| "failed to configure gateway request")?;
+    handle.timeout(Duration::from_secs(30)).chain_err(|| "failed to configure gateway request")?;
+
+    let mut callback_err = None;
+    {
+        let mut transfer = handle.transfer();
+        transfer.write_function(|buf| {
+            match data(buf) {
+                Ok(()) => Ok(buf.len()),
+                Err(e) => {
+                    callback_err = Some(e);
+                    Ok(0)
+                }
+            }
+        }).chain_err(|| "failed to configure gateway request")?;
+        transfer.perform().chain_err(|| format!("failed to reach gateway `{}`", url))?;
+    }
+    if let Some(e) = callback_err {
+        return Err(e);
+    }
+
+    let code = handle.response_code().chain_err(|| "failed to read gateway response")?;
+    if code != 200 {
+        bail!("gateway request to `{}` failed with status {}", url, code);
+    }
+    Ok(())
+}
+
+/// A small client for the local IPFS daemon's HTTP API.
+///
+/// This only knows how to speak the handful of `/api/v0/*` endpoints that
+/// the registry needs; it is not a general-purpose IPFS client.
+struct IPFSApiClient {
+    api_addr: String,
+}
+
+impl IPFSApiClient {
+    fn new(config: &Config) -> CargoResult<IPFSApiClient> {
+        let api_addr = config.get_string("registries.ipfs.api")?
+            .map(|cv| cv.val)
+            .unwrap_or_else(|| DEFAULT_API_ADDR.to_string());
+        Ok(IPFSApiClient { api_addr })
+    }
+
+    /// Calls `/api/v0/cat?arg=<path>`, streaming the returned bytes of a
+    /// single file into `data`.
+    fn cat(&self, path: &str, data: &mut FnMut(&[u8]) -> CargoResult<()>) -> CargoResult<()> {
+        self.get(&format!("cat?arg={}", path), data)
+    }
+
+    /// Calls `/api/v0/get?arg=<path>`, which returns a tar stream of the
+    /// (possibly directory) object at `path`, and unpacks it into `dest`.
+    fn get_archive(&self, path: &str, dest: &Path) -> CargoResult<()> {
+        let mut body = Vec::new();
+        self.get(&format!("get?arg={}&archive=true", path), &mut |buf| {
+            body.extend_from_slice(buf);
+            Ok(())
+        })?;
+        unpack_wrapped_archive(&body, dest).chain_err(|| {
+            "failed to unpack archive returned by the IPFS API"
+        })
+    }
+
+    /// Query flags shared by every `/api/v0/add` call so that the CID it
+    /// computes is always derived the same way: CIDv1 with raw leaves.
+    /// Using identical flags on the add side guarantees `local_cid`, which
+    /// mirrors this chunking/hashing scheme without the daemon, lands on
+    /// the same CID the daemon did.
+    const ADD_HASHING_FLAGS: &'static str = "cid-version=1&raw-leaves=true";
+
+    /// Adds a single file to IPFS (without wrapping it in a directory) and
+    /// returns its CID.
+    fn add_file(&self, path: &Path) -> CargoResult<String> {
+        let filename = path.file_name()
+            .ok_or_else(|| format_err!("cannot add `{}`: not a named file", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+
+        let mut form = Form::new();
+        form.part("file")
+            .file(path)
+            .filename(&filename)
+            .add()
+            .chain_err(|| format!("failed to read `{}`", path.display()))?;
+        let added = self.add(form, Self::ADD_HASHING_FLAGS)?;
+        added.into_iter()
+            .find(|&(ref name, _)| *name == filename)
+            .map(|(_, cid)| cid)
+            .ok_or_else(|| format_err!("IPFS API did not return a hash for `{}`", path.display()))
+    }
+
+    /// Recursively adds a whole directory tree to IPFS, preserving its
+    /// structure, and returns the CID of the directory root.
+    fn add_dir(&self, dir: &Path) -> CargoResult<String> {
+        let root_name = dir.file_name()
+            .ok_or_else(|| format_err!("cannot add `{}`: not a named directory", dir.display()))?
+            .to_string_lossy()
+            .into_owned();
+
+        let mut form = Form::new();
+        let mut any_files = false;
+        for entry in walk_files(dir)? {
+            let rel = entry.strip_prefix(dir).unwrap();
+            let form_path = Path::new(&root_name).join(rel);
+            form.part("file")
+                .file(&entry)
+                .filename(&form_path.to_string_lossy())
+                .add()
+                .chain_err(|| format!("failed to read `{}`", entry.display()))?;
+            any_files = true;
+        }
+        if !any_files {
+            bail!("refusing to publish an empty registry directory `{}`", dir.display());
+        }
+
+        let query = format!("recursive=true&wrap-with-directory=true&{}", Self::ADD_HASHING_FLAGS);
+        let added = self.add(form, &query)?;
+        added.into_iter()
+            .find(|&(ref name, _)| *name == root_name)
+            .map(|(_, cid)| cid)
+            .ok_or_else(|| format_err!("IPFS API did not return a hash for the directory root"))
+    }
+
+    /// POSTs `form` to `/api/v0/add?<query>` and returns the `(name, hash)`
+    /// pairs from the newline-delimited JSON response, one per added entry.
+    fn add(&self, form: Form, query: &str) -> CargoResult<Vec<(String, String)>> {
+        let url = format!("{}/api/v0/add?{}", self.api_addr, query);
+        let mut handle = Easy::new();
+        handle.url(&url).chain_err(|| "failed to configure IPFS API request")?;
+        handle.httppost(form).chain_err(|| "failed to configure IPFS API request")?;
+        handle.timeout(Duration::from_secs(120)).chain_err(|| "failed to configure IPFS API request")?;
+
+        let mut body = Vec::new();
+        {
+            let mut transfer = handle.transfer();
+            transfer.write_function(|buf| {
+                body.extend_from_slice(buf);
+                Ok(buf.len())
+            }).chain_err(|| "failed to configure IPFS API request")?;
+            transfer.perform().chain_err(|| {
+                format!("failed to connect to the IPFS API at {} \
+                         (is the daemon running?)", self.api_addr)
+            })?;
+        }
+
+        let code = handle.response_code().chain_err(|| "failed to read IPFS API response")?;
+        if code != 200 {
+            bail!("IPFS API request to `{}` failed with status {}", url, code);
+        }
+
+        let body = String::from_utf8_lossy(&body);
+        let mut results = Vec::new();
+        for line in body.lines().filter(|l| !l.trim().is_empty()) {
+            let value: Value = serde_json::from_str(line)
+                .chain_err(|| "failed to parse IPFS API response")?;
+            let name = value["Name"].as_str().unwrap_or("").to_string();
+            let hash = value["Hash"].as_str()
+                .ok_or_else(|| format_err!("IPFS API response missing `Hash`"))?
+                .to_string();
+            results.push((name, hash));
+        }
+        Ok(results)
+    }
+
+    /// Pins `cid` so it is not swept up by the daemon's garbage collector.
+    fn pin(&self, cid: &str) -> CargoResult<()> {
+        self.get(&format!("pin/add?arg={}", cid), &mut |_| Ok(()))
+    }
+
+    /// Resolves an IPNS name to the `/ipfs/<cid>` path it currently points
+    /// at. When `force` is set, `nocache=true` is passed so the daemon
+    /// bypasses its local DHT resolution cache and asks the network for the
+    /// latest record.
+    fn resolve_ipns(&self, name: &str, force: bool) -> CargoResult<String> {
+        let url = format!("name/resolve?arg={}&nocache={}", name, force);
+        let mut body = Vec::new();
+        self.get(&url, &mut |buf| {
+            body.extend_from_slice(buf);
+            Ok(())
+        }).chain_err(|| format!("failed to resolve IPNS name `{}`", name))?;
+
+        let body = String::from_utf8_lossy(&body);
+        let value: Value = serde_json::from_str(&body)
+            .chain_err(|| "failed to parse IPFS API response")?;
+        value["Path"].as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| format_err!("IPFS API did not return a resolved path for `{}`", name))
+    }
+
+    fn get(&self,
+           endpoint_and_query: &str,
+           data: &mut FnMut(&[u8]) -> CargoResult<()>) -> CargoResult<()> {
+        let url = format!("{}/api/v0/{}", self.api_addr, endpoint_and_query);
+        let mut handle = Easy::new();
+        handle.url(&url).chain_err(|| "failed to configure IPFS API request")?;
+        handle.post(true).chain_err(|| "failed to configure IPFS API request")?;
+        handle.post_field_size(0).chain_err(|| "failed to configure IPFS API request")?;
+        handle.timeout(Duration::from_secs(30)).chain_err(|| "failed to configure IPFS API request")?;
+
+        let mut callback_err = None;
+        {
+            let mut transfer = handle.transfer();
+            transfer.write_function(|buf| {
+                match data(buf) {
+                    Ok(()) => Ok(buf.len()),
+                    Err(e) => {
+                        callback_err = Some(e);
+                        Ok(0)
+                    }
+                }
+            }).chain_err(|| "failed to configure IPFS API request")?;
+            transfer.perform().chain_err(|| {
+                format!("failed to connect to the IPFS API at {} \
+                         (is the daemon running?)", self.api_addr)
+            })?;
+        }
+        if let Some(e) = callback_err {
+            return Err(e);
+        }
+
+        let code = handle.response_code().chain_err(|| "failed to read IPFS API response")?;
+        if code != 200 {
+            bail!("IPFS API request to `{}` failed with status {}", url, code);
+        }
+        Ok(())
+    }
+}
+
+/// Unpacks a tar archive of the shape returned by `ipfs get`/gateway
+/// `?format=tar` requests, which wrap the requested object in a directory
+/// named after its last path component, into `dest`.
+fn unpack_wrapped_archive(tar_bytes: &[u8], dest: &Path) -> CargoResult<()> {
+    let unpack_root = dest.with_extension("ipfs-get-tmp");
+    let _ = fs::remove_dir_all(&unpack_root);
+    fs::create_dir_all(&unpack_root)?;
+    Archive::new(tar_bytes).unpack(&unpack_root)?;
+    let wrapped = fs::read_dir(&unpack_root)?.next()
+        .ok_or_else(|| format_err!("IPFS returned an empty archive"))??
+        .path();
+    let _ = fs::remove_dir_all(dest);
+    fs::rename(&wrapped, dest)?;
+    fs::remove_dir_all(&unpack_root)?;
+    Ok(())
+}
+
+/// Recursively lists every regular file under `dir`.
+fn walk_files(dir: &Path) -> CargoResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            files.extend(walk_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Computes the path of a crate's index file relative to the registry
+/// index root, following the same nesting scheme as the on-disk local and
+/// remote registries (1/2/3-letter names get their own shallow buckets,
+/// everything else is split into two two-letter directories).
+fn index_file_path(name: &str) -> PathBuf {
+    let lower = name.to_lowercase();
+    match lower.len() {
+        1 => Path::new("1").join(&lower),
+        2 => Path::new("2").join(&lower),
+        3 => Path::new("3").join(&lower[..1]).join(&lower),
+        _ => Path::new(&lower[0..2]).join(&lower[2..4]).join(&lower),
+    }
+}
+
+/// The chunk size and per-node fanout `ipfs add`'s default "balanced"
+/// importer uses, mirrored here so `local_cid` reconstructs the same UnixFS
+/// DAG shape the daemon would for the same `ADD_HASHING_FLAGS`.
+const UNIXFS_CHUNK_SIZE: usize = 256 * 1024;
+const UNIXFS_MAX_LINKS: usize = 174;
+
+/// One node of the UnixFS DAG being assembled bottom-up in `local_cid`.
+struct DagNode {
+    /// This node's CID, serialized the same way a `PBLink.Hash` would be.
+    link_hash: Vec<u8>,
+    /// Total size in bytes of this node's serialized form plus everything
+    /// beneath it, i.e. what a `PBLink.Tsize` pointing at it would read.
+    tsize: u64,
+    /// Sum of the raw file bytes in this node's subtree.
+    filesize: u64,
+    cid: Cid,
+}
+
+impl DagNode {
+    fn leaf(bytes: &[u8]) -> DagNode {
+        let cid = raw_leaf_cid(bytes);
+        DagNode {
+            link_hash: cid.to_bytes(),
+            tsize: bytes.len() as u64,
+            filesize: bytes.len() as u64,
+            cid: cid,
+        }
+    }
+
+    fn branch(children: &[DagNode]) -> DagNode {
+        let filesize: u64 = children.iter().map(|c| c.filesize).sum();
+        let blocksizes: Vec<u64> = children.iter().map(|c| c.filesize).collect();
+        let data = unixfs_file_data(filesize, &blocksizes);
+        let node_bytes = dag_pb_node(&data, children);
+        let tsize = node_bytes.len() as u64 + children.iter().map(|c| c.tsize).sum::<u64>();
+        let hash = multihash::encode(multihash::Hash::SHA2256, &node_bytes)
+            .expect("sha2-256 hashing never fails");
+        let cid = Cid::new(Codec::DagProtobuf, Version::V1, &hash);
+        DagNode {
+            link_hash: cid.to_bytes(),
+            tsize: tsize,
+            filesize: filesize,
+            cid: cid,
+        }
+    }
+}
+
+fn raw_leaf_cid(bytes: &[u8]) -> Cid {
+    let hash = multihash::encode(multihash::Hash::SHA2256, bytes)
+        .expect("sha2-256 hashing never fails");
+    Cid::new(Codec::Raw, Version::V1, &hash)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn write_pb_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(buf, (field << 3) | 2);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_pb_varint_field(buf: &mut Vec<u8>, field: u64, v: u64) {
+    write_varint(buf, field << 3);
+    write_varint(buf, v);
+}
+
+/// Encodes a UnixFS `Data` protobuf message (`unixfs.pb.go`'s `Data`) for a
+/// `File`-typed node: `Type = File`, `filesize`, and one `blocksizes` entry
+/// per child.
+fn unixfs_file_data(filesize: u64, blocksizes: &[u64]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_pb_varint_field(&mut buf, 1, 2); // Type = File
+    write_pb_varint_field(&mut buf, 3, filesize);
+    for blocksize in blocksizes {
+        write_pb_varint_field(&mut buf, 4, *blocksize);
+    }
+    buf
+}
+
+/// Encodes a dag-pb `PBNode` (`merkledag.pb.go`): one `PBLink` per child,
+/// each carrying the child's hash, an empty name (balanced-tree nodes don't
+/// name their children), and its `Tsize`, followed by the node's `Data`.
+fn dag_pb_node(data: &[u8], children: &[DagNode]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for child in children {
+        let mut link = Vec::new();
+        write_pb_bytes_field(&mut link, 1, &child.link_hash);
+        write_pb_bytes_field(&mut link, 2, b"");
+        write_pb_varint_field(&mut link, 3, child.tsize);
+        write_pb_bytes_field(&mut buf, 2, &link);
+    }
+    write_pb_bytes_field(&mut buf, 1, data);
+    buf
+}
+
+/// Computes the CID that `ipfs add` with `IPFSApiClient::ADD_HASHING_FLAGS`
+/// would assign to `bytes`: a raw-leaf CIDv1 if `bytes` fits in a single
+/// chunk, otherwise the root of the balanced UnixFS DAG the daemon's
+/// default chunker/importer would build over it.
+///
+/// This is computed entirely locally (no daemon round-trip) so `download`
+/// can verify a crate's CID even when it was fetched through an untrusted
+/// gateway with no reachable daemon to ask.
+fn local_cid(bytes: &[u8]) -> CargoResult<Cid> {
+    let chunks: Vec<&[u8]> = if bytes.is_empty() {
+        vec![&[][..]]
+    } else {
+        bytes.chunks(UNIXFS_CHUNK_SIZE).collect()
+    };
+    if chunks.len() == 1 {
+        return Ok(raw_leaf_cid(chunks[0]));
+    }
+
+    let mut level: Vec<DagNode> = chunks.iter().map(|c| DagNode::leaf(c)).collect();
+    while level.len() > 1 {
+        level = level.chunks(UNIXFS_MAX_LINKS).map(DagNode::branch).collect();
+    }
+    Ok(level.into_iter().next().unwrap().cid)
+}
+
 pub struct IPFSRegistry<'cfg> {
     ipfs_path: PathBuf,
     local_root: Filesystem,
     config: &'cfg Config,
     local_registry: LocalRegistry<'cfg>,
+    api: IPFSApiClient,
+    gateways: Vec<String>,
 }
 
 impl<'cfg> IPFSRegistry<'cfg> {
     pub fn new(ipfs_path: &Path,
                config: &'cfg Config,
-               name: &str) -> IPFSRegistry<'cfg> {
+               name: &str) -> CargoResult<IPFSRegistry<'cfg>> {
         let local_root = config.registry_ipfs_path().join(name);
-        IPFSRegistry {
+        let gateways = config.get_list("registries.ipfs.gateways")?
+            .map(|cv| cv.val.into_iter().map(|(s, _)| s).collect())
+            .unwrap_or_else(|| DEFAULT_GATEWAYS.iter().map(|s| s.to_string()).collect());
+        Ok(IPFSRegistry {
             ipfs_path: ipfs_path.to_owned(),
             local_root: local_root.clone(),
             config: config,
             local_registry: LocalRegistry::new(&local_root.into_path_unlocked(), config, name),
+            api: IPFSApiClient::new(config)?,
+            gateways: gateways,
+        })
+    }
+
+    /// Resolves `self.ipfs_path` to an immutable `/ipfs/<cid>` path via the
+    /// local daemon.
+    ///
+    /// Immutable `/ipfs/...` paths are returned as-is, since their content
+    /// can be cached indefinitely and never needs a network round-trip.
+    /// Mutable `/ipns/...` names are resolved through the daemon; `force`
+    /// bypasses the daemon's local resolution cache so the result reflects
+    /// the newest publish, which matters for `update_index` but isn't worth
+    /// the extra latency on every single crate download.
+    ///
+    /// Resolving an `/ipns/...` name always goes through the daemon, so
+    /// callers that want to work on machines without one must fall back to
+    /// `self.ipfs_path` unresolved (see `fetch_into`) rather than calling
+    /// this directly.
+    fn resolved_root(&self, force: bool) -> CargoResult<PathBuf> {
+        let path_str = self.ipfs_path.to_string_lossy().into_owned();
+        if path_str.starts_with("/ipns/") {
+            let name = &path_str["/ipns/".len()..];
+            let resolved = self.api.resolve_ipns(name, force)?;
+            Ok(PathBuf::from(resolved))
+        } else {
+            Ok(self.ipfs_path.clone())
         }
     }
+
+    /// Fetches the IPFS object at `self.ipfs_path.join(sub_path)` into
+    /// `dst`, preferring the local daemon but falling back to
+    /// `self.gateways` if the daemon can't be reached *or* (for
+    /// `/ipns/...` registries) can't resolve the name. Gateways resolve
+    /// IPNS names themselves, so on fallback the possibly-still-`/ipns/...`
+    /// `self.ipfs_path` is handed to them directly rather than routing
+    /// through `resolved_root`'s daemon-only resolution — otherwise an
+    /// IPNS-addressed registry could never use the gateway fallback at all,
+    /// since resolution would already have failed before `cat` was tried.
+    fn fetch_into(&self, force: bool, sub_path: &Path, dst: &mut FileLock) -> CargoResult<()> {
+        let local_attempt = self.resolved_root(force).and_then(|root| {
+            let full_path = root.join(sub_path);
+            self.api.cat(&full_path.to_string_lossy(), &mut |buf| {
+                dst.write_all(buf).chain_err(|| {
+                    format!("failed to write `{}`", dst.path().display())
+                })
+            })
+        });
+        let local_err = match local_attempt {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        debug!("local IPFS API unavailable, falling back to gateways: {}", local_err);
+        dst.file().set_len(0)?;
+        dst.seek(SeekFrom::Start(0))?;
+        let full_path = self.ipfs_path.join(sub_path);
+        fetch_via_gateways(&self.gateways, &full_path, "", &mut |buf| {
+            dst.write_all(buf).chain_err(|| {
+                format!("failed to write `{}`", dst.path().display())
+            })
+        }).chain_err(|| format!("also failed to reach the local IPFS API: {}", local_err))
+    }
+
+    fn index_root(&self) -> PathBuf {
+        self.local_root.clone().into_path_unlocked().join("index")
+    }
+
+    /// Looks up the CID recorded for `pkg` in the local copy of the index,
+    /// if any. Older index entries published before CID verification was
+    /// added won't have one.
+    fn index_entry_cid(&self, pkg: &PackageId) -> CargoResult<Option<String>> {
+        let index_file = self.index_root().join(index_file_path(&pkg.name()));
+        let contents = match fs::read_to_string(&index_file) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            let entry: Value = serde_json::from_str(line)
+                .chain_err(|| format!("failed to parse `{}`", index_file.display()))?;
+            if entry["vers"].as_str() == Some(&pkg.version().to_string()) {
+                return Ok(entry["cid"].as_str().map(|s| s.to_string()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Publishes `crate_file` (an already-packaged `.crate` archive) to this
+    /// IPFS registry: adds and pins the crate contents, appends
+    /// `index_line` to the crate's entry in the local copy of the index,
+    /// stages the crate alongside it, and re-publishes the *whole* registry
+    /// root (index *and* crate files) to IPFS.
+    ///
+    /// Because the whole root is republished wholesale, the local copy of
+    /// the index must be current before this appends to it — a stale or
+    /// missing local index would silently drop other crates/versions from
+    /// the republished registry root. If a local index already exists this
+    /// refreshes it first and fails if that refresh fails. Without a local
+    /// index it tries to fetch one, and only if that fetch fails (as on a
+    /// brand new registry with nothing published yet) is the index
+    /// bootstrapped as empty instead.
+    ///
+    /// The crate must live in the republished tree at the same relative
+    /// path (`<name>-<version>.crate`, next to `index/`) that `download`
+    /// fetches it from — otherwise the new version would be discoverable
+    /// through the index but not actually retrievable.
+    ///
+    /// Returns the CID of the republished registry root; callers are
+    /// responsible for pointing the registry's IPNS name (if any) at it.
+    pub fn publish(&mut self,
+                    pkg: &PackageId,
+                    crate_file: &Path,
+                    index_line: &str) -> CargoResult<String> {
+        self.config.shell().status("Publishing", format!("{} to IPFS", pkg))?;
+
+        let index_root = self.index_root();
+        if index_root.exists() {
+            self.update_index().chain_err(|| {
+                "failed to refresh the registry index before publishing"
+            })?;
+        } else if let Err(e) = self.update_index() {
+            debug!("no registry index to fetch, starting an empty one: {}", e);
+            fs::create_dir_all(&index_root)?;
+        }
+
+        let crate_cid = self.api.add_file(crate_file)
+            .chain_err(|| format!("failed to add `{}` to IPFS", crate_file.display()))?;
+        self.api.pin(&crate_cid)
+            .chain_err(|| format!("failed to pin `{}`", crate_cid))?;
+        debug!("published {} as {}", pkg, crate_cid);
+
+        // Stamp the CID onto the index entry so that future downloads can
+        // verify they got the exact content this publish added, not just
+        // content with a matching SHA256.
+        let mut entry: Value = serde_json::from_str(index_line)
+            .chain_err(|| "failed to parse index entry to publish")?;
+        entry["cid"] = Value::String(crate_cid.clone());
+        let mut line = serde_json::to_string(&entry)
+            .chain_err(|| "failed to serialize index entry to publish")?;
+
+        let rel_index_file = index_file_path(&pkg.name());
+        let index_file = index_root.join(&rel_index_file);
+        if let Some(parent) = index_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        line.push('\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index_file)
+            .chain_err(|| format!("failed to open `{}`", index_file.display()))?
+            .write_all(line.as_bytes())
+            .chain_err(|| format!("failed to update `{}`", index_file.display()))?;
+
+        let root = self.local_root.clone().into_path_unlocked();
+        let filename = format!("{}-{}.crate", pkg.name(), pkg.version());
+        fs::create_dir_all(&root)?;
+        fs::copy(crate_file, root.join(&filename)).chain_err(|| {
+            format!("failed to stage `{}` for publish", filename)
+        })?;
+
+        let root_cid = self.api.add_dir(&root)
+            .chain_err(|| "failed to publish the updated registry root to IPFS")?;
+        self.api.pin(&root_cid)
+            .chain_err(|| format!("failed to pin updated registry root `{}`", root_cid))?;
+
+        Ok(root_cid)
+    }
 }
 
 impl<'cfg> RegistryData for IPFSRegistry<'cfg> {
@@ -51,17 +682,30 @@ impl<'cfg> RegistryData for IPFSRegistry<'cfg> {
     }
 
     fn update_index(&mut self) -> CargoResult<()> {
-        // TODO: force update for ipns
-        let temp_path = self.local_root.clone().into_path_unlocked().join("index").clone();
-        let local_path = temp_path.to_string_lossy().clone();
-
-        let output = Command::new("ipget")
-                     .arg(self.ipfs_path.join("index").clone())
-                     .args(&["-o", &local_path])
-                     .output()
-                     .expect("failed to execute process");
-
-        debug!("ipget output: {:?}", output);
+        // `cargo update` needs to see the newest publish, so force the
+        // daemon to bypass its DHT resolution cache for IPNS-addressed
+        // registries when it's reachable. If it's not, fall back to the
+        // gateways the same way `fetch_into` does: hand them the possibly
+        // still-`/ipns/...` `self.ipfs_path` directly rather than routing
+        // through the daemon-only `resolved_root`, since that resolve is
+        // exactly what's failing when the daemon is down.
+        let index_root = self.index_root();
+        let local_attempt = self.resolved_root(true).and_then(|root| {
+            let full_path = root.join("index");
+            self.api.get_archive(&full_path.to_string_lossy(), &index_root)
+        });
+        if let Err(local_err) = local_attempt {
+            debug!("local IPFS API unavailable, falling back to gateways: {}", local_err);
+            let mut body = Vec::new();
+            let full_path = self.ipfs_path.join("index");
+            fetch_via_gateways(&self.gateways, &full_path, "?format=tar", &mut |buf| {
+                body.extend_from_slice(buf);
+                Ok(())
+            }).chain_err(|| format!("also failed to reach the local IPFS API: {}", local_err))?;
+            unpack_wrapped_archive(&body, &index_root).chain_err(|| {
+                "failed to unpack archive returned by an IPFS gateway"
+            })?;
+        }
 
         // Verify if it matches the expectations of a local registry
         self.local_registry.update_index()
@@ -85,21 +729,19 @@ impl<'cfg> RegistryData for IPFSRegistry<'cfg> {
             return Ok(dst)
         }
 
-        // Crate not there. Downloading it from IPFS
+        // Crate not there. Downloading it from IPFS. A cached (non-forced)
+        // IPNS resolution is good enough here: crate contents referenced by
+        // an up-to-date index are already content-addressed downstream.
         self.config.shell().status("Retrieving from IPFS", pkg)?;
-        let temp_path = self.local_root.clone().into_path_unlocked().join(path).clone();
-        let local_path = temp_path.to_string_lossy().clone();
-        let output = Command::new("ipget")
-                     .arg(self.ipfs_path.join(path).clone())
-                     .args(&["-o", &local_path])
-                     .output()
-                     .expect("failed to execute process");
-
-        debug!("ipget output: {:?}", output);
+        self.fetch_into(false, path, &mut dst).chain_err(|| {
+            format!("failed to fetch `{}` from IPFS", pkg)
+        })?;
 
-        // Verify checksum; Somewhat redundant for IPFS, but helps ensure that ipget fully downloaded the file
+        // Verify checksum; helps catch a truncated or corrupted transfer.
         self.config.shell().status("Unpacking", pkg)?;
+        dst.seek(SeekFrom::Start(0))?;
         let mut state = Sha256::new();
+        let mut contents = Vec::new();
         let mut buf = [0; 64 * 1024];
         loop {
             let n = dst.read(&mut buf).chain_err(|| {
@@ -109,11 +751,30 @@ impl<'cfg> RegistryData for IPFSRegistry<'cfg> {
                 break
             }
             state.update(&buf[..n]);
+            contents.extend_from_slice(&buf[..n]);
         }
         if state.finish().to_hex() != checksum {
             bail!("failed to verify the checksum of `{}`", pkg)
         }
 
+        // The checksum only proves the bytes are internally consistent; it
+        // doesn't prove they're the content this registry's index actually
+        // points at. Recompute the CID locally (not via the daemon: this
+        // must also work when `contents` came from a gateway with no
+        // reachable daemon) and compare it against the index entry, so a
+        // misbehaving gateway can't substitute different (but
+        // checksum-matching, e.g. replayed) content.
+        if let Some(expected_cid) = self.index_entry_cid(pkg)? {
+            let actual_cid = local_cid(&contents).chain_err(|| {
+                format!("failed to verify the IPFS CID of `{}`", pkg)
+            })?.to_string();
+            if actual_cid != expected_cid {
+                bail!("failed to verify the IPFS CID of `{}`\n\
+                       expected: {}\n\
+                       actual:   {}", pkg, expected_cid, actual_cid)
+            }
+        }
+
         dst.seek(SeekFrom::Start(0))?;
         Ok(dst)
     }